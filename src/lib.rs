@@ -26,29 +26,92 @@
 //! Creating an `EmptyBox` from a `Box` and then putting a `T` back into the
 //! `EmptyBox` will avoid allocating a new `Box`, instead reusing whatever old
 //! `Box` the `T` was `EmptyBox::take`n from.
+//!
+//! ## The `allocator_api` feature
+//!
+//! By default `EmptyBox<T>` is hardwired to the global allocator, just like
+//! `Box<T>`. Enabling the (nightly-only) `allocator_api` feature swaps in
+//! `EmptyBox<T, A>`, which carries its allocator alongside the pointer so
+//! that `Box<T, A>`s allocated from an arena, a bump allocator, or a kernel
+//! `kmalloc` can be round-tripped through `take`/`put` without ever touching
+//! the global allocator.
+
+#![no_std]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+use alloc::boxed::Box;
+use core::alloc::Layout;
+use core::mem;
+use core::pin::Pin;
+use core::ptr;
 
-use std::mem;
-use std::ptr;
+#[cfg(feature = "allocator_api")]
+use alloc::alloc::Global;
+#[cfg(feature = "allocator_api")]
+use core::alloc::{AllocError, Allocator};
+#[cfg(feature = "allocator_api")]
+use core::mem::ManuallyDrop;
 
 
 /// An "emptied" `Box`. Constructed via `EmptyBox::take()`, an `EmptyBox<T>` is
 /// a `Box` from which the contents have been moved. This allows for reuse of the
 /// `Box` via `EmptyBox::put()`, which moves the contents back in, turning the
 /// `EmptyBox` back into a `Box<T>`.
+#[cfg(not(feature = "allocator_api"))]
 pub struct EmptyBox<T> {
     ptr: *mut T,
 }
 
+/// An "emptied" `Box`. Constructed via `EmptyBox::take()`, an `EmptyBox<T, A>`
+/// is a `Box<T, A>` from which the contents have been moved, with the
+/// allocator `A` that produced it carried alongside the pointer. This allows
+/// for reuse of the allocation via `EmptyBox::put()`, which moves the
+/// contents back in, turning the `EmptyBox` back into a `Box<T, A>` allocated
+/// by the very same `A`.
+#[cfg(feature = "allocator_api")]
+pub struct EmptyBox<T, A: Allocator = Global> {
+    ptr: *mut T,
+    alloc: ManuallyDrop<A>,
+}
+
 
+#[cfg(not(feature = "allocator_api"))]
 impl<T> Drop for EmptyBox<T> {
     fn drop(&mut self) {
-        let boxed = unsafe { Box::from_raw(self.ptr) };
-        let inner = *boxed;
-        mem::forget(inner);
+        // Safety: the slot may hold leftover bits from a different type (see
+        // `reuse_for`), so we must free the allocation without ever reading
+        // it as a `T`.
+        unsafe {
+            drop(Box::from_raw(self.ptr as *mut mem::MaybeUninit<T>));
+        }
     }
 }
 
+#[cfg(feature = "allocator_api")]
+impl<T, A: Allocator> Drop for EmptyBox<T, A> {
+    fn drop(&mut self) {
+        // Safety: `alloc` is only ever taken (via `ManuallyDrop::take`) in
+        // `put`/`reuse_for`/`put_with`, which first wrap `self` in a
+        // `ManuallyDrop` so this `drop` never runs for it, so `drop` only
+        // ever observes a still-live `alloc`.
+        let alloc = unsafe { ManuallyDrop::take(&mut self.alloc) };
+
+        // Safety: the slot may hold leftover bits from a different type (see
+        // `reuse_for`) or be entirely uninitialized (see
+        // `try_new_uninit_in`), so we must free the allocation without ever
+        // reading it as a `T`.
+        unsafe {
+            drop(Box::from_raw_in(self.ptr as *mut mem::MaybeUninit<T>, alloc));
+        }
+    }
+}
 
+
+#[cfg(not(feature = "allocator_api"))]
 impl<T> EmptyBox<T> {
     /// Move the value out of the `Box`, creating a `T` and an `EmptyBox` which
     /// preserves the original `Box`'s allocation.
@@ -70,6 +133,257 @@ impl<T> EmptyBox<T> {
             Box::from_raw(ptr)
         }
     }
+
+
+    /// Reinterpret the emptied allocation as a slot for a different type
+    /// `U`, succeeding iff `T` and `U` have the same size and alignment. On
+    /// success the returned `EmptyBox<U>` reuses the exact same heap block;
+    /// on mismatch, the original `EmptyBox<T>` is handed back unchanged so
+    /// the caller can fall back to allocating fresh.
+    ///
+    /// This also does the right thing for zero-sized types: `Layout`
+    /// equality still holds, the stored pointer is reinterpreted as a
+    /// dangling-but-aligned sentinel for `U`, and no allocator call is ever
+    /// made for either type.
+    pub fn reuse_for<U>(self) -> Result<EmptyBox<U>, EmptyBox<T>> {
+        if Layout::new::<U>() == Layout::new::<T>() {
+            let ptr = self.ptr as *mut U;
+            mem::forget(self);
+            Ok(EmptyBox { ptr })
+        } else {
+            Err(self)
+        }
+    }
+
+
+    /// Like [`put`](EmptyBox::put), but the replacement value is produced
+    /// *after* the slot is ready by `f`. The value `f` returns is then moved
+    /// into the reused allocation; no new allocation is made, but the move
+    /// itself is not elided.
+    ///
+    /// If `f` panics, the allocation is still reclaimed: a guard frees the
+    /// raw `Box` allocation before the panic propagates, without dropping
+    /// any value, since none was ever written into the slot.
+    pub fn put_with<F: FnOnce() -> T>(self, f: F) -> Box<T> {
+        let ptr = self.ptr;
+        mem::forget(self);
+
+        struct FreeGuard<T> {
+            ptr: *mut T,
+        }
+
+        impl<T> Drop for FreeGuard<T> {
+            fn drop(&mut self) {
+                // Safety: nothing has been written into the slot yet, so we
+                // free the allocation without running `T`'s destructor.
+                unsafe {
+                    drop(Box::from_raw(self.ptr as *mut mem::MaybeUninit<T>));
+                }
+            }
+        }
+
+        let guard = FreeGuard { ptr };
+        let t = f();
+        mem::forget(guard);
+
+        unsafe {
+            ptr::write(ptr, t);
+            Box::from_raw(ptr)
+        }
+    }
+
+
+    /// Restore a value to an `EmptyBox`, pinning it in place. This is always
+    /// safe: the value has just been placed into the allocation and has
+    /// never been observed at any other address, so it can never have been
+    /// relied upon to stay put.
+    pub fn put_pinned(self, t: T) -> Pin<Box<T>> {
+        Box::into_pin(self.put(t))
+    }
+
+
+    /// Move the value out of a pinned `Box`, reusing its allocation. Only
+    /// available for `T: Unpin`, since moving a `!Unpin` value out from
+    /// behind a `Pin` would violate the pinning guarantee.
+    pub fn take_pinned(bx: Pin<Box<T>>) -> (T, EmptyBox<T>)
+    where
+        T: Unpin,
+    {
+        EmptyBox::take(Pin::into_inner(bx))
+    }
+
+
+    /// Move the value out of a pinned `Box` without requiring `T: Unpin`,
+    /// reusing its allocation.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the moved-out value will never again
+    /// be relied upon to stay at its old address (for example, because it is
+    /// about to be dropped, or because no other code retained pointers
+    /// derived from its pinned address).
+    pub unsafe fn take_pinned_unchecked(bx: Pin<Box<T>>) -> (T, EmptyBox<T>) {
+        EmptyBox::take(Pin::into_inner_unchecked(bx))
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<T, A: Allocator> EmptyBox<T, A> {
+    /// Move the value out of the `Box`, creating a `T` and an `EmptyBox`
+    /// which preserves the original `Box`'s allocation *and* its allocator.
+    /// The allocator is moved out of `bx`, never cloned, so the allocation is
+    /// later freed through the exact same allocator instance that produced
+    /// it.
+    pub fn take(bx: Box<T, A>) -> (T, EmptyBox<T, A>) {
+        let (ptr, alloc) = Box::into_raw_with_allocator(bx);
+        let t = unsafe { ptr::read(ptr) };
+        (t, EmptyBox { ptr, alloc: ManuallyDrop::new(alloc) })
+    }
+
+
+    /// Allocate a fresh empty slot directly from `a`, without first building
+    /// and then emptying a full `Box<T, A>`. Unlike `put`ting into an
+    /// existing emptied slot, this performs a real allocation and so can
+    /// fail; the failure is surfaced as `AllocError` rather than aborting,
+    /// letting callers pre-reserve a reusable slot up front and handle
+    /// out-of-memory gracefully.
+    pub fn try_new_uninit_in(a: A) -> Result<EmptyBox<T, A>, AllocError> {
+        let layout = Layout::new::<T>();
+        let ptr = a.allocate(layout)?.as_ptr() as *mut u8 as *mut T;
+        Ok(EmptyBox { ptr, alloc: ManuallyDrop::new(a) })
+    }
+
+
+    /// Restore a value to an `EmptyBox`, creating a new `Box` and reusing
+    /// both the allocation and the allocator of whatever `Box` was destroyed
+    /// to create the `EmptyBox`.
+    pub fn put(self, t: T) -> Box<T, A> {
+        let ptr = self.ptr;
+        let mut this = ManuallyDrop::new(self);
+
+        // Safety: `this` is a `ManuallyDrop`, so `Drop::drop` never runs and
+        // `alloc` is taken out exactly once here.
+        let alloc = unsafe { ManuallyDrop::take(&mut this.alloc) };
+
+        unsafe {
+            ptr::write(ptr, t);
+            Box::from_raw_in(ptr, alloc)
+        }
+    }
+
+
+    /// Like [`put`](EmptyBox::put), but fallible for symmetry with
+    /// [`try_new_uninit_in`](EmptyBox::try_new_uninit_in). Putting a value
+    /// into an *already emptied* slot never allocates, so this can never
+    /// actually fail; it always returns `Ok`.
+    pub fn try_put(self, t: T) -> Result<Box<T, A>, AllocError> {
+        Ok(self.put(t))
+    }
+
+
+    /// Reinterpret the emptied allocation as a slot for a different type
+    /// `U`, succeeding iff `T` and `U` have the same size and alignment. The
+    /// allocator is carried over unchanged, so the returned `EmptyBox<U, A>`
+    /// will later be freed through the exact same allocator instance as
+    /// before. On mismatch, the original `EmptyBox<T, A>` is handed back.
+    pub fn reuse_for<U>(self) -> Result<EmptyBox<U, A>, EmptyBox<T, A>> {
+        if Layout::new::<U>() == Layout::new::<T>() {
+            let ptr = self.ptr as *mut U;
+            let mut this = ManuallyDrop::new(self);
+
+            // Safety: `this` is a `ManuallyDrop`, so `alloc` is taken out
+            // exactly once and never dropped in place.
+            let alloc = unsafe { ManuallyDrop::take(&mut this.alloc) };
+
+            Ok(EmptyBox { ptr, alloc: ManuallyDrop::new(alloc) })
+        } else {
+            Err(self)
+        }
+    }
+
+
+    /// Like [`put`](EmptyBox::put), but the replacement value is produced
+    /// *after* the slot is ready by `f`. The value `f` returns is then moved
+    /// into the reused allocation; no new allocation is made, but the move
+    /// itself is not elided.
+    ///
+    /// If `f` panics, the allocation is still reclaimed through `A`: a guard
+    /// frees the raw `Box` allocation before the panic propagates, without
+    /// dropping any value, since none was ever written into the slot.
+    pub fn put_with<F: FnOnce() -> T>(self, f: F) -> Box<T, A> {
+        let ptr = self.ptr;
+        let mut this = ManuallyDrop::new(self);
+        let alloc = unsafe { ManuallyDrop::take(&mut this.alloc) };
+
+        struct FreeGuard<T, A: Allocator> {
+            ptr: *mut T,
+            alloc: ManuallyDrop<A>,
+        }
+
+        impl<T, A: Allocator> Drop for FreeGuard<T, A> {
+            fn drop(&mut self) {
+                // Safety: nothing has been written into the slot yet, so we
+                // free the allocation without running `T`'s destructor.
+                let alloc = unsafe { ManuallyDrop::take(&mut self.alloc) };
+                unsafe {
+                    drop(Box::from_raw_in(
+                        self.ptr as *mut mem::MaybeUninit<T>,
+                        alloc,
+                    ));
+                }
+            }
+        }
+
+        let guard = FreeGuard { ptr, alloc: ManuallyDrop::new(alloc) };
+        let t = f();
+        let mut guard = ManuallyDrop::new(guard);
+
+        // Safety: `guard` is a `ManuallyDrop`, so its `Drop` impl never runs
+        // and `alloc` is taken out exactly once here.
+        let alloc = unsafe { ManuallyDrop::take(&mut guard.alloc) };
+
+        unsafe {
+            ptr::write(ptr, t);
+            Box::from_raw_in(ptr, alloc)
+        }
+    }
+
+
+    /// Restore a value to an `EmptyBox`, pinning it in place. This is always
+    /// safe: the value has just been placed into the allocation and has
+    /// never been observed at any other address, so it can never have been
+    /// relied upon to stay put.
+    pub fn put_pinned(self, t: T) -> Pin<Box<T, A>>
+    where
+        A: 'static,
+    {
+        Box::into_pin(self.put(t))
+    }
+
+
+    /// Move the value out of a pinned `Box`, reusing its allocation and
+    /// allocator. Only available for `T: Unpin`, since moving a `!Unpin`
+    /// value out from behind a `Pin` would violate the pinning guarantee.
+    pub fn take_pinned(bx: Pin<Box<T, A>>) -> (T, EmptyBox<T, A>)
+    where
+        T: Unpin,
+    {
+        EmptyBox::take(Pin::into_inner(bx))
+    }
+
+
+    /// Move the value out of a pinned `Box` without requiring `T: Unpin`,
+    /// reusing its allocation and allocator.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the moved-out value will never again
+    /// be relied upon to stay at its old address (for example, because it is
+    /// about to be dropped, or because no other code retained pointers
+    /// derived from its pinned address).
+    pub unsafe fn take_pinned_unchecked(bx: Pin<Box<T, A>>) -> (T, EmptyBox<T, A>) {
+        EmptyBox::take(Pin::into_inner_unchecked(bx))
+    }
 }
 
 
@@ -136,4 +450,156 @@ mod test {
 
         assert_eq!(counter.get(), 2);
     }
+
+
+    #[test]
+    fn reuse_for_same_layout() {
+        let boxed = Box::new(1u32);
+        let original_ptr = &*boxed as *const u32;
+
+        let (_, empty) = EmptyBox::take(boxed);
+        let empty: EmptyBox<i32> = empty.reuse_for::<i32>().ok().unwrap();
+
+        let boxed = empty.put(-1);
+        assert_eq!(*boxed, -1);
+        assert_eq!(&*boxed as *const i32 as *const u32, original_ptr);
+    }
+
+
+    #[test]
+    fn reuse_for_mismatched_layout() {
+        let boxed = Box::new(1u8);
+        let (_, empty) = EmptyBox::take(boxed);
+
+        let empty = empty.reuse_for::<u64>().err().unwrap();
+
+        assert_eq!(*empty.put(2u8), 2);
+    }
+
+
+    #[test]
+    fn reuse_for_zero_sized() {
+        let boxed = Box::new(());
+        let (_, empty) = EmptyBox::take(boxed);
+
+        let empty: EmptyBox<[u8; 0]> = empty.reuse_for::<[u8; 0]>().ok().unwrap();
+
+        assert_eq!(*empty.put([]), []);
+    }
+
+
+    #[test]
+    fn drop_after_reuse_for_without_put() {
+        // Regression test: dropping a reused `EmptyBox` must never read the
+        // leftover bits of the original type as the new type, since those
+        // bits may not be a valid instance of it (here, `200u8` is not a
+        // valid `bool`).
+        let (_, empty) = EmptyBox::take(Box::new(200u8));
+        mem::drop(empty.reuse_for::<bool>().ok().unwrap());
+    }
+
+
+    #[test]
+    fn put_with_writes_value() {
+        let boxed = Box::new(1u32);
+        let (_, empty) = EmptyBox::take(boxed);
+
+        let boxed = empty.put_with(|| 2 + 2);
+
+        assert_eq!(*boxed, 4);
+    }
+
+
+    #[test]
+    fn put_with_reclaims_allocation_on_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let boxed = Box::new(0u8);
+        let (_, empty) = EmptyBox::take(boxed);
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            empty.put_with(|| -> u8 { panic!("boom") })
+        }));
+
+        assert!(result.is_err());
+    }
+
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn reuses_allocator() {
+        let boxed = Box::new_in(1u32, Global);
+        let original_ptr = &*boxed as *const u32;
+
+        let (value, empty) = EmptyBox::take(boxed);
+        assert_eq!(value, 1);
+
+        let boxed = empty.put(2u32);
+        assert_eq!(*boxed, 2);
+        assert_eq!(&*boxed as *const u32, original_ptr);
+    }
+
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn try_new_uninit_in_then_put() {
+        let empty: EmptyBox<u32, Global> = EmptyBox::try_new_uninit_in(Global).unwrap();
+
+        let boxed = empty.try_put(42).ok().unwrap();
+
+        assert_eq!(*boxed, 42);
+    }
+
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn drop_after_try_new_uninit_in_without_put() {
+        // Regression test: `try_new_uninit_in` hands back a genuinely
+        // uninitialized slot, so dropping it without a `put` must never read
+        // its contents as `T`.
+        let empty: EmptyBox<u32, Global> = EmptyBox::try_new_uninit_in(Global).unwrap();
+        mem::drop(empty);
+    }
+
+
+    #[test]
+    fn put_pinned_then_take_pinned() {
+        let boxed = Box::new(1u32);
+        let original_ptr = &*boxed as *const u32;
+
+        let (_, empty) = EmptyBox::take(boxed);
+        let pinned = empty.put_pinned(2u32);
+
+        assert_eq!(*pinned, 2);
+
+        let (value, empty) = EmptyBox::take_pinned(pinned);
+        assert_eq!(value, 2);
+
+        let boxed = empty.put(3u32);
+        assert_eq!(&*boxed as *const u32, original_ptr);
+    }
+
+
+    #[test]
+    fn take_pinned_unchecked_reuses_allocation() {
+        let counter = Cell::new(0);
+        let boxed = Box::new(DropCounter(&counter));
+        let original_ptr = &*boxed as *const DropCounter;
+
+        let (dc, empty) = EmptyBox::take(boxed);
+        mem::drop(dc);
+        let pinned = empty.put_pinned(DropCounter(&counter));
+
+        assert_eq!(counter.get(), 1);
+
+        // Safety: the dropcounter is about to be dropped and nothing else
+        // observes its pinned address.
+        let (dc, empty) = unsafe { EmptyBox::take_pinned_unchecked(pinned) };
+        mem::drop(dc);
+
+        assert_eq!(counter.get(), 2);
+
+        let boxed = empty.put(DropCounter(&counter));
+        assert_eq!(&*boxed as *const DropCounter, original_ptr);
+    }
 }